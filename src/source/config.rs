@@ -0,0 +1,102 @@
+//! Configuration of a [`Source`](super::Source).
+
+use std::time::Duration;
+
+use ffmpeg::{codec, format::Pixel};
+
+use crate::io::frame::video::FourCCVideoType;
+
+/// Parameters used to expose a [`Source`](super::Source) on the network.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// The human-readable name advertised for the source.
+    pub name: String,
+
+    /// How the source is made discoverable to sinks on the network.
+    pub discovery: Discovery,
+
+    /// Description of the encoder used to compress broadcast video frames.
+    pub video: VideoEncoder,
+
+    /// Window over which a peer is considered _flowing_: a media kind whose
+    /// byte tally did not advance within this duration is reported as stopped.
+    pub flow_window: Duration,
+
+    /// When set, peers that have been stopped for longer than this duration are
+    /// pruned from the [`Source`](super::Source).
+    pub prune_timeout: Option<Duration>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            name: String::default(),
+            discovery: Discovery::default(),
+            video: VideoEncoder::default(),
+            flow_window: Duration::from_secs(2),
+            prune_timeout: None,
+        }
+    }
+}
+
+/// How a [`Source`](super::Source) is exposed to potential sinks.
+///
+/// On locked-down or routed networks mDNS is often unavailable, in which case
+/// [`Discovery::Manual`] binds a known port for out-of-band sharing instead of
+/// advertising the service.
+#[derive(Debug, Clone)]
+pub enum Discovery {
+    /// Advertise the source over mDNS, in the given groups (defaulting to
+    /// `public`). Group names may be computed at runtime.
+    Mdns { groups: Option<Vec<String>> },
+
+    /// Skip advertisement entirely and bind the provided port, `0` letting the
+    /// OS pick one; the resulting address is exposed through
+    /// [`Source::addr`](super::Source::addr).
+    Manual { bind_port: u16 },
+}
+
+impl Default for Discovery {
+    fn default() -> Self {
+        Self::Mdns { groups: None }
+    }
+}
+
+/// Description of the video encoder pipeline used by a [`Source`](super::Source).
+///
+/// Defaults to the SpeedHQ / `YUV422P` / `SHQ2` combination NDI sinks expect,
+/// but a source may pick a lower-quality profile or an alternate supported
+/// codec by overriding the relevant fields.
+#[derive(Debug, Clone)]
+pub struct VideoEncoder {
+    /// The codec used to encode outgoing frames.
+    pub codec: codec::Id,
+
+    /// The pixel format frames are converted to before encoding.
+    pub format: Pixel,
+
+    /// The NDI `FourCC` stamped into the emitted [`video::Spec`](crate::io::frame::video::Spec).
+    pub fourcc: FourCCVideoType,
+
+    /// Target bitrate in bits per second, if the codec honours it.
+    pub bitrate: Option<usize>,
+
+    /// Encoder quality hint, if the codec honours it.
+    pub quality: Option<usize>,
+
+    /// Interval, in frames, between keyframes.
+    pub keyframe_interval: Option<u32>,
+}
+
+impl Default for VideoEncoder {
+    fn default() -> Self {
+        Self {
+            codec: codec::Id::SPEEDHQ,
+            format: Pixel::YUV422P,
+            fourcc: FourCCVideoType::SHQ2,
+            bitrate: None,
+            quality: None,
+            keyframe_interval: None,
+        }
+    }
+}
@@ -0,0 +1,224 @@
+//! Per-peer traffic metrics and flow detection for a [`Source`](super::Source).
+//!
+//! Counters are updated from the hot `listen` fan-out loop, so they are kept as
+//! lock-free atomics and only materialised into a [`Stats`] snapshot on demand.
+//! Whether a given media kind is _flowing_ or _stopped_ is derived, in the
+//! spirit of an RTC flow detector, by checking that a successful send advanced
+//! its byte tally within a configurable window.
+
+use std::{
+    sync::atomic::{AtomicI64, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+
+use crate::io::frame::FrameKind;
+
+/// Atomically-updated traffic counters for a single connected peer.
+#[derive(Debug, Default)]
+pub(super) struct Metrics {
+    video: Counters,
+    audio: Counters,
+    text: Counters,
+    /// Milliseconds since the epoch at which the peer connected, used as the
+    /// prune clock for peers that never manage a successful send.
+    connected_at: AtomicI64,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    frames: AtomicU64,
+    bytes: AtomicU64,
+    skipped: AtomicU64,
+    errors: AtomicU64,
+    /// Milliseconds since the epoch of the last successful send, `0` if never.
+    last_send: AtomicI64,
+}
+
+impl Metrics {
+    /// Create a fresh set of counters, stamping the connection time.
+    pub(super) fn new() -> Self {
+        let metrics = Self::default();
+        metrics
+            .connected_at
+            .store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+
+        metrics
+    }
+
+    fn counters(&self, kind: FrameKind) -> &Counters {
+        match kind {
+            FrameKind::Video => &self.video,
+            FrameKind::Audio => &self.audio,
+            FrameKind::Text => &self.text,
+        }
+    }
+
+    /// Record a successful send of `bytes` for the given media `kind`.
+    pub(super) fn record_sent(&self, kind: FrameKind, bytes: usize) {
+        let counters = self.counters(kind);
+
+        counters.frames.fetch_add(1, Ordering::Relaxed);
+        counters.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        counters
+            .last_send
+            .store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+    }
+
+    /// Record a frame dropped by the stream-enable gate.
+    pub(super) fn record_skipped(&self, kind: FrameKind) {
+        self.counters(kind).skipped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a failed send for the given media `kind`.
+    pub(super) fn record_error(&self, kind: FrameKind) {
+        self.counters(kind).errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Milliseconds since the epoch of the most recent successful send, across
+    /// all kinds, `0` if never.
+    pub(super) fn last_send_millis(&self) -> i64 {
+        [&self.video, &self.audio, &self.text]
+            .iter()
+            .map(|counters| counters.last_send.load(Ordering::Relaxed))
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Milliseconds since the epoch of the peer's last activity: its most recent
+    /// successful send, or its connection time if it never sent anything. Used
+    /// to prune peers that stalled immediately on connect as well as ones that
+    /// went quiet after flowing.
+    pub(super) fn last_activity_millis(&self) -> i64 {
+        self.last_send_millis()
+            .max(self.connected_at.load(Ordering::Relaxed))
+    }
+
+    /// Snapshot the counters into a [`Stats`], resolving the flow state of each
+    /// media kind against `window`.
+    pub(super) fn snapshot(&self, name: String, window: Duration) -> Stats {
+        let now = Utc::now().timestamp_millis();
+
+        Stats {
+            name,
+            video: self.video.snapshot(window, now),
+            audio: self.audio.snapshot(window, now),
+            text: self.text.snapshot(window, now),
+        }
+    }
+}
+
+impl Counters {
+    fn snapshot(&self, window: Duration, now: i64) -> MediaStats {
+        let last = self.last_send.load(Ordering::Relaxed);
+
+        let flow = if last != 0 && now.saturating_sub(last) <= window.as_millis() as i64 {
+            Flow::Flowing
+        } else {
+            Flow::Stopped
+        };
+
+        MediaStats {
+            frames: self.frames.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            skipped: self.skipped.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            last_send: (last != 0)
+                .then(|| DateTime::from_timestamp_millis(last))
+                .flatten(),
+            flow,
+        }
+    }
+}
+
+/// A point-in-time snapshot of a connected peer's traffic, as returned by
+/// [`Source::stats`](super::Source::stats).
+#[derive(Debug, Clone)]
+pub struct Stats {
+    /// The peer's advertised name.
+    pub name: String,
+    /// Video traffic counters and flow state.
+    pub video: MediaStats,
+    /// Audio traffic counters and flow state.
+    pub audio: MediaStats,
+    /// Text/metadata traffic counters and flow state.
+    pub text: MediaStats,
+}
+
+/// Traffic counters and derived flow state for a single media kind.
+#[derive(Debug, Clone)]
+pub struct MediaStats {
+    /// Frames successfully sent.
+    pub frames: u64,
+    /// Bytes successfully sent.
+    pub bytes: u64,
+    /// Frames dropped by the stream-enable gate.
+    pub skipped: u64,
+    /// Failed sends.
+    pub errors: u64,
+    /// Timestamp of the last successful send, if any.
+    pub last_send: Option<DateTime<Utc>>,
+    /// Whether data advanced within the configured window.
+    pub flow: Flow,
+}
+
+/// Whether a media kind is actively being pulled by a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    /// Bytes advanced within the configured window.
+    Flowing,
+    /// No bytes advanced within the window — the sink may be backed up.
+    Stopped,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flow_window_is_inclusive_at_the_boundary() {
+        let window = Duration::from_secs(2);
+
+        let counters = Counters::default();
+        counters.last_send.store(1_000, Ordering::Relaxed);
+
+        // Exactly at the window edge still counts as flowing.
+        assert_eq!(counters.snapshot(window, 3_000).flow, Flow::Flowing);
+        // One millisecond past the edge is stopped.
+        assert_eq!(counters.snapshot(window, 3_001).flow, Flow::Stopped);
+    }
+
+    #[test]
+    fn never_sent_counters_are_stopped() {
+        let counters = Counters::default();
+        let stats = counters.snapshot(Duration::from_secs(2), 5_000);
+
+        assert_eq!(stats.flow, Flow::Stopped);
+        assert!(stats.last_send.is_none());
+    }
+
+    #[test]
+    fn last_send_millis_tracks_the_most_recent_kind() {
+        let metrics = Metrics::default();
+        assert_eq!(metrics.last_send_millis(), 0);
+
+        metrics.video.last_send.store(10, Ordering::Relaxed);
+        metrics.audio.last_send.store(42, Ordering::Relaxed);
+
+        assert_eq!(metrics.last_send_millis(), 42);
+    }
+
+    #[test]
+    fn last_activity_falls_back_to_connection_time() {
+        let metrics = Metrics::default();
+        metrics.connected_at.store(7, Ordering::Relaxed);
+
+        // With no successful send, activity is the connection time.
+        assert_eq!(metrics.last_activity_millis(), 7);
+
+        // A later send supersedes it.
+        metrics.record_sent(FrameKind::Video, 16);
+        assert!(metrics.last_activity_millis() >= metrics.last_send_millis());
+    }
+}
@@ -1,23 +1,33 @@
 //! Everything related to NDI [`Source`]s, to send video.
 
-use std::sync::{Arc, Weak};
+use std::{
+    net::{Ipv6Addr, SocketAddr},
+    sync::{Arc, Weak},
+};
 
 use ffmpeg::codec;
 use futures::{StreamExt, TryFutureExt};
 use mdns_sd::{ServiceDaemon, ServiceInfo, UnregisterStatus};
 use slab::Slab;
-use tokio::{net::TcpListener, sync::RwLock};
+use tokio::{
+    net::TcpListener,
+    sync::{Mutex, RwLock},
+};
 
 use crate::{
     io::{
-        frame::{text, video, Frame, FrameKind},
+        frame::{audio, text, video, Frame, FrameKind},
         Stream,
     },
     Error, Result,
 };
 
 mod config;
-pub use config::Config;
+pub use config::{Config, Discovery};
+
+mod metrics;
+pub use metrics::{Flow, MediaStats, Stats};
+use metrics::Metrics;
 
 mod peer;
 pub use peer::Peer;
@@ -25,49 +35,152 @@ pub use peer::Peer;
 type Lock<T> = Arc<RwLock<T>>;
 type WeakLock<T> = Weak<RwLock<T>>;
 
+/// How often stalled peers are checked for pruning.
+const PRUNE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// The payload size, in bytes, carried by a [`Frame`]'s block.
+fn payload_len(frame: &Frame) -> usize {
+    match frame {
+        Frame::Video(block) => block.data.len(),
+        Frame::Audio(block) => block.data.len(),
+        Frame::Text(block) => block.data.len(),
+    }
+}
+
 /// A _video_ and _audio_ source, that can send data to multiple sinks.
 pub struct Source {
     name: String,
-    mdns: ServiceDaemon,
+    addr: SocketAddr,
+    mdns: Option<ServiceDaemon>,
 
+    video: config::VideoEncoder,
+    encoder: Arc<Mutex<Option<Encoder>>>,
+
+    flow_window: std::time::Duration,
     peers: Lock<Vec<WeakLock<Peer>>>,
+    stats: Lock<Vec<(WeakLock<Peer>, Weak<Metrics>)>>,
     frames: flume::Sender<Frame>,
 }
 
+/// A persistent video encoder, reused across broadcasts so the codec is opened
+/// once rather than re-allocated for every frame.
+struct Encoder {
+    inner: codec::encoder::Video,
+    width: u32,
+    height: u32,
+    format: ffmpeg::format::Pixel,
+    framerate: ffmpeg::Rational,
+}
+
+impl Encoder {
+    /// Open an encoder for the resolved `config` against a sample `frame`.
+    fn open(
+        config: &config::VideoEncoder,
+        frame: &ffmpeg::frame::Video,
+        framerate: ffmpeg::Rational,
+    ) -> Result<Self> {
+        let mut context = codec::Context::new().encoder().video()?;
+        context.set_time_base(framerate);
+        context.set_format(config.format);
+        context.set_width(frame.width());
+        context.set_height(frame.height());
+
+        if let Some(bitrate) = config.bitrate {
+            context.set_bit_rate(bitrate);
+        }
+        if let Some(quality) = config.quality {
+            context.set_quality(quality);
+        }
+        if let Some(interval) = config.keyframe_interval {
+            context.set_gop(interval);
+        }
+
+        let inner = context.open_as(codec::encoder::find(config.codec))?;
+
+        Ok(Self {
+            inner,
+            width: frame.width(),
+            height: frame.height(),
+            format: config.format,
+            framerate,
+        })
+    }
+
+    /// Whether this encoder was opened for the same geometry as `frame`, and can
+    /// therefore be reused as-is.
+    fn fits(&self, frame: &ffmpeg::frame::Video, framerate: ffmpeg::Rational) -> bool {
+        self.width == frame.width()
+            && self.height == frame.height()
+            && self.format == frame.format()
+            && self.framerate == framerate
+    }
+}
+
 impl Source {
     /// Expose a new [`Source`] based on the provided `config` on the network.
     pub async fn new(config: Config) -> Result<Self> {
-        let groups = config.groups.as_deref().unwrap_or(&["public"]).join(",");
-        let listener = TcpListener::bind("[::]:0").await?;
+        let bind_port = match config.discovery {
+            Discovery::Manual { bind_port } => bind_port,
+            Discovery::Mdns { .. } => 0,
+        };
+        let listener = TcpListener::bind(SocketAddr::from((Ipv6Addr::UNSPECIFIED, bind_port))).await?;
+        let addr = listener.local_addr()?;
+
+        let (name, mdns) = match &config.discovery {
+            Discovery::Mdns { groups } => {
+                let groups = match groups {
+                    Some(groups) if !groups.is_empty() => groups.join(","),
+                    _ => "public".to_owned(),
+                };
+
+                let mdns = ServiceDaemon::new()?;
+                let service = ServiceInfo::new(
+                    super::SERVICE_TYPE,
+                    &crate::name(&config.name),
+                    &crate::hostname(),
+                    (),
+                    addr.port(),
+                    [("groups", groups.as_str())].as_slice(),
+                )?
+                .enable_addr_auto();
+
+                let name = service.get_fullname().into();
+                mdns.register(service)?;
+
+                tracing::debug!("Registered mDNS service `{}`", name);
+
+                (name, Some(mdns))
+            }
+            Discovery::Manual { .. } => {
+                let name = crate::name(&config.name);
 
-        let mdns = ServiceDaemon::new()?;
-        let service = ServiceInfo::new(
-            super::SERVICE_TYPE,
-            &crate::name(&config.name),
-            &crate::hostname(),
-            (),
-            listener.local_addr()?.port(),
-            [("groups", groups.as_str())].as_slice(),
-        )?
-        .enable_addr_auto();
+                tracing::debug!("Exposing source `{}` at `{}` without mDNS", name, addr);
 
-        let name = service.get_fullname().into();
-        mdns.register(service)?;
+                (name, None)
+            }
+        };
 
-        tracing::debug!("Registered mDNS service `{}`", name);
+        let video = config.video.clone();
+        let flow_window = config.flow_window;
 
         let peers = <Lock<Vec<WeakLock<Peer>>>>::default();
+        let stats = <Lock<Vec<(WeakLock<Peer>, Weak<Metrics>)>>>::default();
         let (frames, framesrx) = flume::bounded(1);
 
         tokio::spawn(
-            Self::listen(listener, config, peers.clone(), framesrx)
+            Self::listen(listener, config, peers.clone(), stats.clone(), framesrx)
                 .inspect_err(|err| tracing::error!("Fatal error in `Source::listener`: {err}")),
         );
 
         Ok(Self {
             name,
+            addr,
             mdns,
+            video,
+            encoder: Arc::default(),
+            flow_window,
             peers,
+            stats,
             frames,
         })
     }
@@ -76,9 +189,11 @@ impl Source {
         listener: tokio::net::TcpListener,
         config: Config,
         peers: Lock<Vec<WeakLock<Peer>>>,
+        stats: Lock<Vec<(WeakLock<Peer>, Weak<Metrics>)>>,
         frames: flume::Receiver<Frame>,
     ) -> Result {
-        let mut streams: Slab<(Lock<Peer>, Stream)> = Slab::with_capacity(32);
+        let mut streams: Slab<(Lock<Peer>, Stream, Arc<Metrics>)> = Slab::with_capacity(32);
+        let mut prune = tokio::time::interval(PRUNE_INTERVAL);
 
         loop {
             tokio::select! {
@@ -93,9 +208,34 @@ impl Source {
                     )
                     .await??;
                     let peer = Arc::from(RwLock::new(peer));
+                    let metrics = Arc::new(Metrics::new());
 
                     peers.write().await.push(Arc::downgrade(&peer));
-                    streams.insert((peer, stream));
+                    stats.write().await.push((Arc::downgrade(&peer), Arc::downgrade(&metrics)));
+                    streams.insert((peer, stream, metrics));
+                }
+
+                // Prune sinks that have silently stalled beyond the timeout
+                _ = prune.tick() => {
+                    if let Some(timeout) = config.prune_timeout {
+                        let cutoff = chrono::Utc::now().timestamp_millis() - timeout.as_millis() as i64;
+
+                        streams.retain(|_, (peer, _, metrics)| {
+                            // Fall back to the connection time so a peer that
+                            // never sends is pruned too, not just one that went
+                            // quiet after flowing.
+                            let last = metrics.last_activity_millis();
+                            let stalled = last != 0 && last < cutoff;
+
+                            if stalled {
+                                if let Ok(peer) = peer.try_read() {
+                                    tracing::warn!("Pruning stopped peer `{}`", peer.identify.name);
+                                }
+                            }
+
+                            !stalled
+                        });
+                    }
                 }
 
                 // Receive metadata from peers
@@ -107,7 +247,7 @@ impl Source {
 
                     readable.next().await
                 } => {
-                    let (idx, (peer, stream)) = &mut entry;
+                    let (idx, (peer, stream, _)) = &mut entry;
 
                     match stream.metadata().await {
                         Ok(Some(text::Metadata::Tally(tally))) => {
@@ -131,7 +271,8 @@ impl Source {
                                 let frame = &frame;
 
                                 async move {
-                                    let (peer, stream) = entry;
+                                    let (peer, stream, metrics) = entry;
+                                    let kind = FrameKind::from(frame);
                                     let peer = peer.read().await;
 
                                     if (peer.streams.text && matches!(frame, Frame::Text { .. }))
@@ -140,9 +281,16 @@ impl Source {
                                         tracing::trace!("-> sending {:?} frame to `{}`", frame, peer.identify.name);
 
                                         drop(peer);
-                                        stream.send(frame).await.ok();
+                                        match stream.send(frame).await {
+                                            Ok(()) => metrics.record_sent(kind, payload_len(frame)),
+                                            Err(err) => {
+                                                tracing::debug!("Send to peer failed: {err}");
+                                                metrics.record_error(kind);
+                                            }
+                                        }
                                     } else {
-                                        tracing::trace!("-x-> skip sending {:?} frame to `{}`", FrameKind::from(frame), peer.identify.name);
+                                        tracing::trace!("-x-> skip sending {:?} frame to `{}`", kind, peer.identify.name);
+                                        metrics.record_skipped(kind);
                                     }
                                 }
                             })
@@ -153,6 +301,12 @@ impl Source {
         }
     }
 
+    /// The local [`SocketAddr`] the [`Source`] is bound to, for out-of-band
+    /// sharing when running without mDNS advertisement.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
     /// List the peers currently connected to the [`Source`], with their parameters.
     pub async fn peers(&self) -> Vec<Peer> {
         let pointers: Vec<_> = self
@@ -175,6 +329,31 @@ impl Source {
         peers
     }
 
+    /// Snapshot the per-peer traffic [`Stats`] for every connected peer, so a
+    /// silently-stalled sink can be told apart from an idle one.
+    pub async fn stats(&self) -> Vec<Stats> {
+        // Compact the registry in place so peers registered by `listen`
+        // concurrently are not dropped by a read-then-overwrite race.
+        let pointers: Vec<_> = {
+            let mut registry = self.stats.write().await;
+            registry.retain(|(peer, metrics)| {
+                peer.strong_count() > 0 && metrics.strong_count() > 0
+            });
+
+            registry
+                .iter()
+                .filter_map(|(peer, metrics)| Some((Weak::upgrade(peer)?, Weak::upgrade(metrics)?)))
+                .collect()
+        };
+
+        futures::future::join_all(pointers.iter().map(|(peer, metrics)| async {
+            let name = peer.read().await.identify.name.clone();
+
+            metrics.snapshot(name, self.flow_window)
+        }))
+        .await
+    }
+
     /// Get current _tally_ information computed from all the connected peers of the [`Source`].
     pub async fn tally(&self) -> text::Tally {
         self.peers()
@@ -195,59 +374,108 @@ impl Source {
             frame.width()
         );
 
-        let mut converted = ffmpeg::frame::Video::new(
-            ffmpeg::format::Pixel::YUV422P,
-            frame.width(),
-            frame.height(),
-        );
+        let mut converted =
+            ffmpeg::frame::Video::new(self.video.format, frame.width(), frame.height());
 
         frame
             .converter(converted.format())?
             .run(frame, &mut converted)?;
 
-        let mut context = codec::Context::new().encoder().video()?;
-        context.set_time_base(framerate);
-        context.set_format(converted.format());
-        context.set_width(converted.width());
-        context.set_height(converted.height());
+        // Reuse the persistent encoder unless the frame geometry changed under
+        // us, in which case we transparently re-open it for the new format.
+        let mut guard = self.encoder.lock().await;
+        if !guard.as_ref().is_some_and(|enc| enc.fits(&converted, framerate)) {
+            *guard = Some(Encoder::open(&self.video, &converted, framerate)?);
+        }
+        let encoder = &mut guard.as_mut().expect("encoder just set").inner;
 
-        let mut encoder = context.open_as(codec::encoder::find(codec::Id::SPEEDHQ))?;
         encoder.send_frame(&converted)?;
-        encoder.send_eof()?;
 
+        // Drain every packet the encoder has ready: `receive_packet` reports
+        // `EAGAIN` (→ `Err`) once it needs more frames, which ends the loop, and
+        // codecs with encoder delay or multiple packets per frame are handled
+        // rather than silently truncated to the first packet.
         let mut packet = ffmpeg::Packet::empty();
-        encoder.receive_packet(&mut packet)?;
+        while encoder.receive_packet(&mut packet).is_ok() {
+            if let Some(data) = packet.data() {
+                self.frames
+                    .send_async(Frame::video(
+                        video::Spec {
+                            fourcc: self.video.fourcc,
+                            width: converted.width(),
+                            height: converted.height(),
+                            fps_num: framerate.numerator() as u32,
+                            fps_den: framerate.denominator() as u32,
+                            aspect_ratio: converted.width() as f32 / converted.height() as f32,
+                            frame_format: video::FrameFormat::Progressive,
+                            timestamp: chrono::Utc::now().into(),
+                            ..Default::default()
+                        },
+                        data.to_vec(),
+                    ))
+                    .await
+                    .map_err(|_| Error::ClosedChannel)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Broadcast a [`ffmpeg::frame::Audio`] to all the connected peers.
+    pub async fn broadcast_audio(&self, frame: &ffmpeg::frame::Audio) -> Result {
+        use ffmpeg::format::{sample::Type, Sample};
+
+        // NDI carries audio as 32-bit planar float, one contiguous plane per
+        // channel. A frame without samples has nothing to carry, skip it
+        // instead of emitting an empty block downstream.
+        if frame.samples() == 0 {
+            tracing::trace!("Skipping empty audio frame");
+
+            return Ok(());
+        }
+
+        // Resample towards NDI's native layout; this also folds interleaved
+        // input back into the per-channel planes we expect below.
+        let mut converted = ffmpeg::frame::Audio::empty();
+        frame
+            .resampler(Sample::F32(Type::Planar), frame.channel_layout(), frame.rate())?
+            .run(frame, &mut converted)?;
+
+        let channels = converted.channels() as usize;
+        let stride = converted.samples() * std::mem::size_of::<f32>();
+
+        let mut data = Vec::with_capacity(stride * channels);
+        for plane in 0..converted.planes() {
+            data.extend_from_slice(&converted.data(plane)[..stride]);
+        }
 
         self.frames
-            .send_async(Frame::video(
-                video::Spec {
-                    fourcc: video::FourCCVideoType::SHQ2,
-                    width: converted.width(),
-                    height: converted.height(),
-                    fps_num: framerate.numerator() as u32,
-                    fps_den: framerate.denominator() as u32,
-                    aspect_ratio: converted.width() as f32 / converted.height() as f32,
-                    frame_format: video::FrameFormat::Progressive,
+            .send_async(Frame::audio(
+                audio::Spec {
+                    sample_rate: converted.rate(),
+                    channels: channels as u32,
+                    samples: converted.samples() as u32,
+                    stride: stride as u32,
                     timestamp: chrono::Utc::now().into(),
                     ..Default::default()
                 },
-                packet.data().expect("No packet data ??").to_vec(),
+                data,
             ))
             .await
             .map_err(|_| Error::ClosedChannel)?;
 
         Ok(())
     }
-
-    /// Broadcast a [`ffmpeg::frame::Audio`] to all the connected peers.
-    pub fn broadcast_audio(&self, frame: &ffmpeg::frame::Audio) -> Result {
-        todo!("Broadcast an audio frame")
-    }
 }
 
 impl Drop for Source {
     fn drop(&mut self) {
-        match self.mdns.unregister(&self.name).map(|recv| recv.recv()) {
+        // Nothing to tear down when the source was never advertised.
+        let Some(mdns) = self.mdns.as_ref() else {
+            return;
+        };
+
+        match mdns.unregister(&self.name).map(|recv| recv.recv()) {
             Err(err) => tracing::error!(
                 "Error while unregistering service `{}` from mDNS: {err}",
                 self.name
@@ -264,7 +492,7 @@ impl Drop for Source {
             _ => tracing::debug!("Unregistered mDNS service `{}`", self.name),
         }
 
-        if let Err(err) = self.mdns.shutdown() {
+        if let Err(err) = mdns.shutdown() {
             tracing::error!("Error while shutting down the mDNS advertisement thread: {err}");
         }
     }